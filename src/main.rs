@@ -1,6 +1,7 @@
 // Uncomment this block to pass the first stage
 use anyhow::anyhow;
 use anyhow::Context;
+use flate2::write::GzEncoder;
 use std::collections::HashMap;
 use std::env;
 use std::io::{BufRead, BufReader, Read, Write};
@@ -8,27 +9,147 @@ use std::net::{TcpListener, TcpStream};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Deadline for a single request: an idle keep-alive connection may wait this
+/// long for the next request line, and once a request has begun the remaining
+/// request-line, headers, and body must all arrive within this window. A
+/// client that stalls mid-request is answered with `408 Request Timeout`.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Shrink the stream's read timeout to the time left until `deadline`, so a
+/// sequence of reads is bounded in total rather than each read getting a fresh
+/// window. Errors with `TimedOut` once the deadline has already passed.
+fn arm_deadline(stream: &TcpStream, deadline: Instant) -> std::io::Result<()> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "request deadline exceeded",
+        ));
+    }
+    stream.set_read_timeout(Some(remaining))
+}
+
+/// `true` for the read-timeout error kinds `set_read_timeout` can raise.
+fn is_timeout_kind(kind: std::io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Whether an error (or any of its causes) is a read timeout, so a stalled
+/// mid-request read can be distinguished from a clean close or parse error.
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|io| is_timeout_kind(io.kind()))
+    })
+}
+
+/// A case-insensitive map of HTTP header fields.
+///
+/// Field names are compared case-insensitively per the HTTP spec, so keys are
+/// normalized to lowercase on insert and lookup. The original casing is kept
+/// alongside the value so responses serialize header names as they were set.
+#[derive(Default)]
+struct HeaderMap {
+    inner: HashMap<String, (String, String)>,
+}
+
+impl HeaderMap {
+    fn new() -> Self {
+        HeaderMap {
+            inner: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        let key = name.to_ascii_lowercase();
+        self.inner.insert(key, (name, value.into()));
+    }
+
+    fn get(&self, name: &str) -> Option<&String> {
+        self.inner.get(&name.to_ascii_lowercase()).map(|(_, v)| v)
+    }
+
+    /// Insert `name`/`value` only if the field is not already present.
+    fn insert_if_absent(&mut self, name: &str, value: impl FnOnce() -> String) {
+        self.inner
+            .entry(name.to_ascii_lowercase())
+            .or_insert_with(|| (name.to_string(), value()));
+    }
+
+    /// Iterate over `(original name, value)` pairs for serialization.
+    fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.inner.values().map(|(name, value)| (name, value))
+    }
+}
+
+impl<const N: usize> From<[(String, String); N]> for HeaderMap {
+    fn from(pairs: [(String, String); N]) -> Self {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(name, value);
+        }
+        map
+    }
+}
 
 struct HttpRequest {
     path: String,
     method: String,
-    _version: String,
-    headers: HashMap<String, String>,
+    version: String,
+    headers: HeaderMap,
+    params: HashMap<String, String>,
     body: Vec<u8>,
 }
 
+impl HttpRequest {
+    /// Whether the connection should be kept open after this request.
+    ///
+    /// Honours an explicit `Connection` header, otherwise defaults to
+    /// keep-alive for HTTP/1.1 and close for HTTP/1.0.
+    fn keep_alive(&self) -> bool {
+        match self.headers.get("Connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version.trim() != "HTTP/1.0",
+        }
+    }
+}
+
 impl TryFrom<&mut TcpStream> for HttpRequest {
     type Error = anyhow::Error;
 
     fn try_from(stream: &mut TcpStream) -> Result<Self, Self::Error> {
         let mut reader = BufReader::new(stream);
         let mut request_line = String::new();
+        // A timeout with nothing received means the connection sat idle between
+        // requests, a clean keep-alive close rather than a stalled request.
+        match reader.fill_buf() {
+            Ok([]) => return Err(anyhow!("connection closed")),
+            Ok(_) => {}
+            Err(e) if is_timeout_kind(e.kind()) => return Err(anyhow!("idle connection")),
+            Err(e) => return Err(e).context("Read the request line."),
+        }
+
+        // The request has begun; from here the request-line, headers, and body
+        // must all arrive before this single deadline, so a client trickling
+        // bytes can't reset the clock on every read. A stall propagates as an
+        // io error so it earns a 408 like any other slow-request read.
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        arm_deadline(reader.get_ref(), deadline).context("Request deadline")?;
         reader
             .read_line(&mut request_line)
             .context("Read the request line.")?;
+
         let splits: Vec<_> = request_line.split_whitespace().collect();
         let method = splits
-            .get(0)
+            .first()
             .ok_or_else(|| anyhow!("Could not parse method"))?;
         let path = splits
             .get(1)
@@ -37,10 +158,11 @@ impl TryFrom<&mut TcpStream> for HttpRequest {
             .get(2)
             .ok_or_else(|| anyhow!("Could not parse version"))?;
 
-        let mut headers = HashMap::new();
+        let mut headers = HeaderMap::new();
         let mut header = String::new();
         loop {
             header.clear();
+            arm_deadline(reader.get_ref(), deadline).context("Request deadline")?;
             reader.read_line(&mut header).context("Read header line")?;
 
             if header == "\r\n" {
@@ -51,25 +173,37 @@ impl TryFrom<&mut TcpStream> for HttpRequest {
                 .trim()
                 .split_once(": ")
                 .ok_or_else(|| anyhow!("Could not parse hader value {}", header))?;
-            if name.len() > 0 && content.len() > 0 {
+            if !name.is_empty() && !content.is_empty() {
                 headers.insert(name.to_string(), content.to_string());
             }
         }
 
+        // A client may announce `Expect: 100-continue` and pause before sending
+        // the body; acknowledge it so the upload can proceed instead of hanging.
+        if headers
+            .get("Expect")
+            .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+        {
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .context("Write 100 Continue")?;
+        }
+
         let content_length = headers
             .get("Content-Length")
-            .unwrap_or(&"0".to_string())
-            .parse::<usize>()
+            .map(|v| v.parse::<usize>().unwrap_or(0))
             .unwrap_or(0);
-        println!("Content lenght from request {content_length}");
-        let mut body = Vec::with_capacity(content_length);
+        let mut body = vec![0u8; content_length];
+        arm_deadline(reader.get_ref(), deadline).context("Request deadline")?;
         reader.read_exact(&mut body).context("Read body")?;
 
         Ok(HttpRequest {
             path: path.to_string(),
             method: method.to_string(),
-            _version: version.to_string(),
+            version: version.to_string(),
             headers,
+            params: HashMap::new(),
             body,
         })
     }
@@ -77,24 +211,27 @@ impl TryFrom<&mut TcpStream> for HttpRequest {
 
 struct HttpResponse {
     status_code: u16,
-    headers: HashMap<String, String>,
-    body: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
 }
 
 impl HttpResponse {
-    fn new(status_code: u16, headers: HashMap<String, String>, body: &str) -> Self {
+    fn new(status_code: u16, headers: HeaderMap, body: &str) -> Self {
         HttpResponse {
             status_code,
             headers,
-            body: body.to_string(),
+            body: body.as_bytes().to_vec(),
         }
     }
 
-    fn to_string(&self) -> String {
+    /// Serialize the status line, headers, and (possibly binary) body into the
+    /// bytes to write on the wire.
+    fn to_bytes(&self) -> Vec<u8> {
         let status_message = match self.status_code {
             200 => "OK",
             201 => "Created",
             404 => "Not Found",
+            408 => "Request Timeout",
             _ => "Internal error",
         };
         let headers = self
@@ -104,23 +241,161 @@ impl HttpResponse {
             .collect::<Vec<_>>()
             .join("\r\n");
 
-        format!(
-            "HTTP/1.1 {} {}\r\n{}\r\n\r\n{}",
-            self.status_code, status_message, headers, self.body
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\n{}\r\n\r\n",
+            self.status_code, status_message, headers
         )
+        .into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
     }
 }
 
-trait RequestHandler {
-    fn handle_request(&self, request: &HttpRequest, dir: Arc<Option<String>>) -> HttpResponse;
+/// Shared, read-only application state threaded through every handler.
+///
+/// Today it only carries the serving directory, but it leaves room for future
+/// config such as a default content-type, upload size limits, or shared
+/// counters without changing every handler's signature.
+struct AppState {
+    dir: Option<String>,
+}
+
+/// A route handler parameterized over the application state type `S`, mirroring
+/// actix-web's `HttpApplication<S>`. Handlers that don't need the state simply
+/// ignore it.
+trait RequestHandler<S> {
+    fn handle_request(&self, request: &HttpRequest, state: Arc<S>) -> HttpResponse;
+}
+
+/// A single segment of a route pattern.
+///
+/// Patterns are split on `/` into a sequence of these; `Static` matches an
+/// exact segment, `Param` captures one segment under a name, and `Wildcard`
+/// captures the remainder of the path.
+enum Segment {
+    Static(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl Segment {
+    fn parse(raw: &str) -> Self {
+        if let Some(name) = raw.strip_prefix(':') {
+            Segment::Param(name.to_string())
+        } else if let Some(name) = raw.strip_prefix('*') {
+            Segment::Wildcard(name.to_string())
+        } else {
+            Segment::Static(raw.to_string())
+        }
+    }
+}
+
+struct Route<S> {
+    method: String,
+    segments: Vec<Segment>,
+    handler: Box<dyn RequestHandler<S> + Send + Sync>,
+}
+
+/// Declarative `(Method, pattern)` -> handler table.
+///
+/// Patterns use `/` separated segments where `:name` captures a single
+/// segment and `*name` captures the trailing path. Matching walks segments in
+/// order, preferring a static segment over a `:param` over a `*wildcard`, so
+/// the most specific registered route wins.
+struct Router<S> {
+    routes: Vec<Route<S>>,
+}
+
+impl<S> Router<S> {
+    fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    fn register(
+        &mut self,
+        method: &str,
+        pattern: &str,
+        handler: Box<dyn RequestHandler<S> + Send + Sync>,
+    ) {
+        let segments = Self::split(pattern).map(Segment::parse).collect();
+        self.routes.push(Route {
+            method: method.to_string(),
+            segments,
+            handler,
+        });
+    }
+
+    /// Find the best handler for `method`/`path`, returning it together with
+    /// the captured path parameters.
+    fn recognize(
+        &self,
+        method: &str,
+        path: &str,
+    ) -> Option<(&(dyn RequestHandler<S> + Send + Sync), HashMap<String, String>)> {
+        let path_segments: Vec<&str> = Self::split(path).collect();
+        let mut best: Option<(&Route<S>, HashMap<String, String>, Vec<u8>)> = None;
+        for route in &self.routes {
+            if route.method != method {
+                continue;
+            }
+            if let Some((params, score)) = Self::try_match(&route.segments, &path_segments) {
+                if best.as_ref().map(|(_, _, s)| score > *s).unwrap_or(true) {
+                    best = Some((route, params, score));
+                }
+            }
+        }
+        best.map(|(route, params, _)| (route.handler.as_ref(), params))
+    }
+
+    /// Match a pattern against concrete path segments, returning the captured
+    /// params and a specificity score (higher is more specific): one rank per
+    /// segment (static 3, param 2, wildcard 1), compared in segment order.
+    fn try_match(
+        segments: &[Segment],
+        path: &[&str],
+    ) -> Option<(HashMap<String, String>, Vec<u8>)> {
+        let mut params = HashMap::new();
+        let mut score = Vec::with_capacity(segments.len());
+        for (idx, segment) in segments.iter().enumerate() {
+            match segment {
+                Segment::Static(name) => {
+                    if path.get(idx) != Some(&name.as_str()) {
+                        return None;
+                    }
+                    score.push(3);
+                }
+                Segment::Param(name) => {
+                    let value = path.get(idx)?;
+                    params.insert(name.clone(), (*value).to_string());
+                    score.push(2);
+                }
+                Segment::Wildcard(name) => {
+                    let rest = path.get(idx..).unwrap_or_default().join("/");
+                    params.insert(name.clone(), rest);
+                    score.push(1);
+                    return Some((params, score));
+                }
+            }
+        }
+        if path.len() == segments.len() {
+            Some((params, score))
+        } else {
+            None
+        }
+    }
+
+    fn split(path: &str) -> impl Iterator<Item = &str> {
+        path.split('/').filter(|s| !s.is_empty())
+    }
 }
 
 struct EchoHandler;
 
-impl RequestHandler for EchoHandler {
-    fn handle_request(&self, request: &HttpRequest, _: Arc<Option<String>>) -> HttpResponse {
-        let body = request.path.strip_prefix("/echo/").unwrap_or_default();
-        let headers: HashMap<String, String> = [
+impl RequestHandler<AppState> for EchoHandler {
+    fn handle_request(&self, request: &HttpRequest, _: Arc<AppState>) -> HttpResponse {
+        let empty = String::new();
+        let body = request.params.get("str").unwrap_or(&empty);
+        let headers: HeaderMap = [
             ("Content-Type".to_string(), "text/plain".to_string()),
             ("Content-Length".to_string(), body.len().to_string()),
         ]
@@ -131,11 +406,11 @@ impl RequestHandler for EchoHandler {
 
 struct UserAgentHandler;
 
-impl RequestHandler for UserAgentHandler {
-    fn handle_request(&self, request: &HttpRequest, _: Arc<Option<String>>) -> HttpResponse {
+impl RequestHandler<AppState> for UserAgentHandler {
+    fn handle_request(&self, request: &HttpRequest, _: Arc<AppState>) -> HttpResponse {
         let unknown = "Unknown".to_string();
         let user_agent = request.headers.get("User-Agent").unwrap_or(&unknown);
-        let headers: HashMap<String, String> = [
+        let headers: HeaderMap = [
             ("Content-Type".to_string(), "text/plain".to_string()),
             ("Content-Length".to_string(), user_agent.len().to_string()),
             ("User-Agent".to_string(), user_agent.to_string()),
@@ -147,9 +422,9 @@ impl RequestHandler for UserAgentHandler {
 
 struct SuccessHandler;
 
-impl RequestHandler for SuccessHandler {
-    fn handle_request(&self, _: &HttpRequest, _: Arc<Option<String>>) -> HttpResponse {
-        let headers: HashMap<String, String> =
+impl RequestHandler<AppState> for SuccessHandler {
+    fn handle_request(&self, _: &HttpRequest, _: Arc<AppState>) -> HttpResponse {
+        let headers: HeaderMap =
             [("Content-Type".to_string(), "text/plain".to_string())].into();
         HttpResponse::new(200, headers, "")
     }
@@ -157,9 +432,9 @@ impl RequestHandler for SuccessHandler {
 
 struct NotFoundHandler;
 
-impl RequestHandler for NotFoundHandler {
-    fn handle_request(&self, _: &HttpRequest, _: Arc<Option<String>>) -> HttpResponse {
-        let headers: HashMap<String, String> =
+impl RequestHandler<AppState> for NotFoundHandler {
+    fn handle_request(&self, _: &HttpRequest, _: Arc<AppState>) -> HttpResponse {
+        let headers: HeaderMap =
             [("Content-Type".to_string(), "text/plain".to_string())].into();
         HttpResponse::new(404, headers, "")
     }
@@ -167,17 +442,18 @@ impl RequestHandler for NotFoundHandler {
 
 struct FileGetHander;
 
-impl RequestHandler for FileGetHander {
-    fn handle_request(&self, request: &HttpRequest, dir: Arc<Option<String>>) -> HttpResponse {
-        let file_name = request.path.strip_prefix("/files/").unwrap_or_default();
+impl RequestHandler<AppState> for FileGetHander {
+    fn handle_request(&self, request: &HttpRequest, state: Arc<AppState>) -> HttpResponse {
+        let empty = String::new();
+        let file_name = request.params.get("name").unwrap_or(&empty);
         let fallback = "".to_string();
-        let dir = dir.as_deref().unwrap_or(&fallback);
+        let dir = state.dir.as_deref().unwrap_or(&fallback);
         let path = PathBuf::from(dir).join(file_name);
         let data = std::fs::read_to_string(path);
 
         match data {
             Ok(data) => {
-                let headers: HashMap<String, String> = [
+                let headers: HeaderMap = [
                     (
                         "Content-Type".to_string(),
                         "application/octet-stream".to_string(),
@@ -188,7 +464,7 @@ impl RequestHandler for FileGetHander {
                 HttpResponse::new(200, headers, &data)
             }
             Err(_) => {
-                let headers: HashMap<String, String> =
+                let headers: HeaderMap =
                     [("Content-Type".to_string(), "text/plain".to_string())].into();
                 HttpResponse::new(404, headers, "")
             }
@@ -198,23 +474,24 @@ impl RequestHandler for FileGetHander {
 
 struct FilePostHander;
 
-impl RequestHandler for FilePostHander {
-    fn handle_request(&self, request: &HttpRequest, dir: Arc<Option<String>>) -> HttpResponse {
-        let file_name = request.path.strip_prefix("/files/").unwrap_or_default();
+impl RequestHandler<AppState> for FilePostHander {
+    fn handle_request(&self, request: &HttpRequest, state: Arc<AppState>) -> HttpResponse {
+        let empty = String::new();
+        let file_name = request.params.get("name").unwrap_or(&empty);
         let fallback = "".to_string();
-        let dir = dir.as_deref().unwrap_or(&fallback);
+        let dir = state.dir.as_deref().unwrap_or(&fallback);
         let path = PathBuf::from(dir).join(file_name);
 
         let file = std::fs::File::create(&path).and_then(|mut f| f.write_all(&request.body));
         match file {
             Ok(_) => {
                 println!("Wrote file to {}", path.display());
-                let headers: HashMap<String, String> =
+                let headers: HeaderMap =
                     [("Content-Type".to_string(), "text/plain".to_string())].into();
                 HttpResponse::new(201, headers, "")
             }
             Err(_) => {
-                let headers: HashMap<String, String> =
+                let headers: HeaderMap =
                     [("Content-Type".to_string(), "text/plain".to_string())].into();
                 HttpResponse::new(500, headers, "")
             }
@@ -222,6 +499,268 @@ impl RequestHandler for FilePostHander {
     }
 }
 
+/// A cross-cutting hook that wraps handler invocation.
+///
+/// `before` runs in registration order and may short-circuit the request by
+/// returning a response; `after` runs in reverse order and may rewrite the
+/// response on its way back out.
+trait Middleware {
+    fn before(&self, _req: &mut HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    fn after(&self, _req: &HttpRequest, res: HttpResponse) -> HttpResponse {
+        res
+    }
+}
+
+/// An ordered stack of [`Middleware`] wrapped around a [`RequestHandler`].
+struct Chain {
+    middlewares: Vec<Box<dyn Middleware + Send + Sync>>,
+}
+
+impl Chain {
+    fn new() -> Self {
+        Chain {
+            middlewares: Vec::new(),
+        }
+    }
+
+    fn with(mut self, middleware: Box<dyn Middleware + Send + Sync>) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// Run `before` in order, invoke `handler` (unless a middleware
+    /// short-circuited), then run `after` in reverse order.
+    fn run<S>(
+        &self,
+        mut request: HttpRequest,
+        handler: &dyn RequestHandler<S>,
+        state: Arc<S>,
+    ) -> HttpResponse {
+        let mut response = None;
+        for middleware in &self.middlewares {
+            if let Some(res) = middleware.before(&mut request) {
+                response = Some(res);
+                break;
+            }
+        }
+        let mut response = response.unwrap_or_else(|| handler.handle_request(&request, state));
+        for middleware in self.middlewares.iter().rev() {
+            response = middleware.after(&request, response);
+        }
+        response
+    }
+}
+
+/// Logs each request's method/path and the resulting status code.
+struct Logger;
+
+impl Middleware for Logger {
+    fn before(&self, req: &mut HttpRequest) -> Option<HttpResponse> {
+        println!("--> {} {}", req.method, req.path);
+        None
+    }
+
+    fn after(&self, req: &HttpRequest, res: HttpResponse) -> HttpResponse {
+        println!("<-- {} {} {}", req.method, req.path, res.status_code);
+        res
+    }
+}
+
+/// Adds `Server` and `Date` headers to every response unless the handler
+/// already set them.
+struct DefaultHeaders;
+
+impl Middleware for DefaultHeaders {
+    fn after(&self, _req: &HttpRequest, mut res: HttpResponse) -> HttpResponse {
+        res.headers
+            .insert_if_absent("Server", || "cc-http-server".to_string());
+        res.headers.insert_if_absent("Date", http_date);
+        res
+    }
+}
+
+/// Bodies below this size aren't worth compressing.
+const MIN_COMPRESS_SIZE: usize = 64;
+
+/// Compresses response bodies with gzip when the client advertises it via
+/// `Accept-Encoding`, leaving small or un-negotiated responses untouched.
+struct Compression;
+
+impl Middleware for Compression {
+    fn after(&self, req: &HttpRequest, mut res: HttpResponse) -> HttpResponse {
+        if res.body.len() < MIN_COMPRESS_SIZE {
+            return res;
+        }
+
+        let accepts_gzip = req
+            .headers
+            .get("Accept-Encoding")
+            .map(|value| parse_accept_encoding(value).iter().any(|c| c == "gzip"))
+            .unwrap_or(false);
+        if !accepts_gzip {
+            return res;
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        match encoder.write_all(&res.body).and_then(|_| encoder.finish()) {
+            Ok(compressed) => {
+                res.body = compressed;
+                res.headers.insert("Content-Encoding", "gzip");
+                res
+            }
+            Err(_) => res,
+        }
+    }
+}
+
+/// Parse an `Accept-Encoding` value into its codings, most-preferred first,
+/// dropping any explicitly refused with `q=0`.
+fn parse_accept_encoding(header: &str) -> Vec<String> {
+    let mut codings: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|part| {
+            let mut pieces = part.split(';');
+            let coding = pieces.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let quality = pieces
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                None
+            } else {
+                Some((coding, quality))
+            }
+        })
+        .collect();
+    codings.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    codings.into_iter().map(|(coding, _)| coding).collect()
+}
+
+/// Format the current time as an RFC 1123 HTTP date (always in GMT).
+fn http_date() -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let days = secs / 86_400;
+    let (hour, min, sec) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    let weekday = ((days + 4) % 7) as usize;
+
+    // Civil-from-days algorithm (Howard Hinnant), epoch shifted to 0000-03-01.
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let year = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = if month <= 2 { year + 1 } else { year };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[weekday],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        min,
+        sec
+    )
+}
+
+fn build_chain() -> Chain {
+    Chain::new()
+        .with(Box::new(Logger))
+        .with(Box::new(Compression))
+        .with(Box::new(DefaultHeaders))
+}
+
+/// Serve requests on a single connection until the peer asks to close, the
+/// connection goes idle, or the stream errors/ends.
+fn handle_connection(
+    mut stream: TcpStream,
+    router: Arc<Router<AppState>>,
+    chain: Arc<Chain>,
+    state: Arc<AppState>,
+) {
+    loop {
+        // Give every request on this connection a full idle/deadline window;
+        // parsing shrinks the timeout as its deadline elapses, so it must be
+        // restored before waiting for the next request line.
+        let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+
+        let mut request = match HttpRequest::try_from(&mut stream) {
+            Ok(request) => request,
+            Err(err) => {
+                // A stalled mid-request read gets a 408 before we hang up; an
+                // idle or closed connection is dropped silently.
+                if is_timeout(&err) {
+                    let mut response =
+                        HttpResponse::new(408, HeaderMap::new(), "");
+                    response
+                        .headers
+                        .insert("Content-Length", "0");
+                    response.headers.insert("Connection", "close");
+                    let _ = stream.write_all(&response.to_bytes());
+                }
+                break;
+            }
+        };
+
+        let keep_alive = request.keep_alive();
+        let mut response = match router.recognize(&request.method, &request.path) {
+            Some((handler, params)) => {
+                request.params = params;
+                chain.run(request, handler, Arc::clone(&state))
+            }
+            None => chain.run(request, &NotFoundHandler, Arc::clone(&state)),
+        };
+
+        // The peers share this socket, so every message needs an accurate
+        // Content-Length and an explicit Connection disposition.
+        response.headers.insert(
+            "Content-Length".to_string(),
+            response.body.len().to_string(),
+        );
+        response.headers.insert(
+            "Connection".to_string(),
+            if keep_alive { "keep-alive" } else { "close" }.to_string(),
+        );
+
+        if stream.write_all(&response.to_bytes()).is_err() {
+            break;
+        }
+
+        if !keep_alive {
+            break;
+        }
+    }
+}
+
+fn build_router() -> Router<AppState> {
+    let mut router = Router::new();
+    router.register("GET", "/", Box::new(SuccessHandler));
+    router.register("GET", "/echo/*str", Box::new(EchoHandler));
+    router.register("GET", "/user-agent", Box::new(UserAgentHandler));
+    router.register("GET", "/files/:name", Box::new(FileGetHander));
+    router.register("POST", "/files/:name", Box::new(FilePostHander));
+    router
+}
+
 fn main() -> anyhow::Result<()> {
     let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
     let args: Vec<_> = env::args().collect();
@@ -229,36 +768,16 @@ fn main() -> anyhow::Result<()> {
         .iter()
         .position(|arg| arg == "--directory")
         .and_then(|idx| args.get(idx + 1).cloned());
-    let dir = Arc::new(dir);
+    let state = Arc::new(AppState { dir });
+    let router = Arc::new(build_router());
+    let chain = Arc::new(build_chain());
 
     for stream in listener.incoming() {
-        let dir_arc = Arc::clone(&dir);
+        let state = Arc::clone(&state);
+        let router = Arc::clone(&router);
+        let chain = Arc::clone(&chain);
         thread::spawn(move || match stream {
-            Ok(mut stream) => {
-                let request = HttpRequest::try_from(&mut stream).unwrap();
-                let response = if request.path.starts_with("/echo") {
-                    EchoHandler.handle_request(&request, dir_arc)
-                } else if request.path == "/user-agent" {
-                    UserAgentHandler.handle_request(&request, dir_arc)
-                } else if request.path.starts_with("/files") {
-                    if request.method == "GET" {
-                        FileGetHander.handle_request(&request, dir_arc)
-                    } else if request.method == "POST" {
-                        FilePostHander.handle_request(&request, dir_arc)
-                    } else {
-                        NotFoundHandler.handle_request(&request, dir_arc)
-                    }
-                } else if request.path == "/" {
-                    SuccessHandler.handle_request(&request, dir_arc)
-                } else {
-                    NotFoundHandler.handle_request(&request, dir_arc)
-                };
-
-                stream
-                    .write_all(response.to_string().as_bytes())
-                    .context("Write response to stream")
-                    .unwrap();
-            }
+            Ok(stream) => handle_connection(stream, router, chain, state),
             Err(e) => {
                 println!("error: {}", e);
             }